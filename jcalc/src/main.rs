@@ -1,23 +1,29 @@
+use std::collections::HashMap;
 use std::io::Write;
 use std::io::stderr;
 use std::io::stdout;
 use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
 use std::vec;
 
 use clap::Parser;
 use futures_util::lock::Mutex;
-use ore_jdwp::defs::ArrayReferenceSetValuesSend;
-use ore_jdwp::defs::ArrayReferenceSetValuesSendValues;
-use ore_jdwp::defs::ArrayTypeNewInstanceReceive;
-use ore_jdwp::defs::ArrayTypeNewInstanceSend;
 use ore_jdwp::defs::ClassTypeInvokeMethodReceive;
 use ore_jdwp::defs::ClassTypeInvokeMethodSend;
 use ore_jdwp::defs::ClassTypeInvokeMethodSendArguments;
+use ore_jdwp::defs::ClassTypeNewInstanceReceive;
+use ore_jdwp::defs::ClassTypeNewInstanceSend;
+use ore_jdwp::defs::ClassTypeNewInstanceSendArguments;
 use ore_jdwp::defs::EventCompositeReceiveEventsEventKind;
+use ore_jdwp::defs::EventRequestClearSend;
+use ore_jdwp::defs::EventRequestSetReceive;
 use ore_jdwp::defs::EventRequestSetSend;
 use ore_jdwp::defs::EventRequestSetSendModifiers;
 use ore_jdwp::defs::EventRequestSetSendModifiersModKind;
 use ore_jdwp::defs::EventRequestSetSendModifiersModKind12;
+use ore_jdwp::defs::ObjectReferenceDisableCollectionSend;
+use ore_jdwp::defs::ObjectReferenceEnableCollectionSend;
 use ore_jdwp::defs::ObjectReferenceInvokeMethodReceive;
 use ore_jdwp::defs::ObjectReferenceInvokeMethodSend;
 use ore_jdwp::defs::ObjectReferenceInvokeMethodSendArguments;
@@ -35,23 +41,24 @@ use ore_jdwp::defs::VirtualMachineClassesBySignatureReceive;
 use ore_jdwp::defs::VirtualMachineClassesBySignatureSend;
 use ore_jdwp::defs::VirtualMachineCreateStringReceive;
 use ore_jdwp::defs::VirtualMachineCreateStringSend;
-use ore_jdwp::packets::ConvPrettyIOValue;
 use ore_jdwp::packets::JDWPIDLengthEqField;
 use ore_jdwp::packets::JDWPIDLengthEqMethod;
 use ore_jdwp::packets::JDWPIDLengthEqObject;
 use ore_jdwp::packets::JDWPIDLengthEqReferenceType;
 use ore_jdwp::packets::JDWPValue;
-use ore_jdwp::packets::PrettyIOKind;
+use tokio::io::AsyncBufRead;
 use tokio::io::AsyncBufReadExt;
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
+use tokio::sync::oneshot;
 
 use ore_jdwp::packets::{JDWPContext, JDWPPacketDataFromDebuggee, JDWPPacketDataFromDebugger};
 use ore_jdwp::packets::{receive_packet, send_packet};
 
 mod parse;
+mod profile;
 
 #[derive(Parser, Debug)]
 #[command(name = "tcp_client")]
@@ -87,6 +94,58 @@ struct Args {
 
   #[arg(short, long, help = "If set, calc desinated expression and exit")]
   expression: Option<String>,
+
+  #[arg(
+    long,
+    default_value = "false",
+    help = "Print time spent per JDWP command on exit"
+  )]
+  profile: bool,
+
+  #[arg(
+    long,
+    default_value = "5000",
+    help = "Timeout in milliseconds for each JDWP request"
+  )]
+  timeout_ms: u64,
+
+  #[arg(
+    long,
+    default_value = "false",
+    help = "Retry connecting until the target jvm is ready"
+  )]
+  retry: bool,
+
+  #[arg(
+    long,
+    default_value = "10000",
+    help = "Give up retrying to connect after this many milliseconds"
+  )]
+  retry_timeout_ms: u64,
+
+  #[arg(
+    long,
+    value_parser = clap::value_parser!(u32).range(2..=36),
+    help = "Print results in the given radix (2-36)"
+  )]
+  radix: Option<u32>,
+
+  #[arg(
+    short,
+    long,
+    conflicts_with = "expression",
+    help = "If set, calc each line of the file and exit"
+  )]
+  file: Option<String>,
+
+  #[arg(
+    short,
+    long,
+    default_value = "false",
+    conflicts_with = "radix",
+    help = "Calc with java.math.BigDecimal instead of BigInteger"
+  )]
+  decimal: bool,
 }
 
 #[tokio::main]
@@ -94,49 +153,88 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
   let args = Args::parse();
   let addr = format!("{}:{}", args.host, args.port);
 
-  let mut stream = TcpStream::connect(addr.clone()).await?;
-  if args.verbose {
-    eprintln!("Connected to {}", addr);
-  }
+  // VM を止める前に、入力ファイルが開けることを確かめておく
+  let batch_file = match args.file {
+    Some(ref path) => Some(
+      tokio::fs::File::open(path)
+        .await
+        .map_err(|e| format!("Failed to open {}: {}", path, e))?,
+    ),
+    None => None,
+  };
+
+  // 接続とハンドシェイク (--retry 時は期限まで指数バックオフで再試行)
+  let deadline = Instant::now() + Duration::from_millis(args.retry_timeout_ms);
+  let mut backoff = Duration::from_millis(100);
+  let mut attempt = 1;
+  let mut last_error = None;
+  let stream = loop {
+    if args.verbose && args.retry {
+      eprintln!("Connecting to {} (attempt {})", addr, attempt);
+    }
+    // 1 回の接続やハンドシェイクで固まっても、期限を超えて待たない
+    let connected = if args.retry {
+      match tokio::time::timeout(
+        deadline.saturating_duration_since(Instant::now()),
+        connect_and_handshake(&addr, args.verbose),
+      )
+      .await
+      {
+        Ok(connected) => connected,
+        // 期限切れなら、直前に失敗した試行の本来のエラーを返す
+        Err(_) => {
+          return Err(
+            last_error.unwrap_or_else(|| Box::from("Timed out connecting to the target jvm")),
+          );
+        }
+      }
+    } else {
+      connect_and_handshake(&addr, args.verbose).await
+    };
+    match connected {
+      Ok(stream) => break stream,
+      Err(e) if args.retry && Instant::now() < deadline => {
+        let wait = backoff.min(deadline.saturating_duration_since(Instant::now()));
+        if args.verbose {
+          eprintln!(
+            "Attempt {} failed: {}. Retrying in {} ms",
+            attempt,
+            e,
+            wait.as_millis()
+          );
+        }
+        last_error = Some(e);
+        tokio::time::sleep(wait).await;
+        backoff = (backoff * 2).min(Duration::from_secs(2));
+        attempt += 1;
+      }
+      Err(e) => return Err(e),
+    }
+  };
 
   let payloads: Arc<Mutex<Vec<JDWPPacketDataFromDebugger>>> = Arc::new(Mutex::new(Vec::new()));
   let context = Arc::new(Mutex::new(JDWPContext { id_sizes: None }));
 
-  // --- Handshake ---
-  let handshake = b"JDWP-Handshake";
-  stream.write_all(handshake).await?;
-  stream.flush().await?;
-  if args.verbose {
-    eprintln!("Sent handshake: {:?}", String::from_utf8_lossy(handshake));
-  }
-
-  // 応答を読む（同期的に一度読む）
-  let mut buf = [0u8; 14];
-  stream.read_exact(&mut buf).await?;
-  if &buf != b"JDWP-Handshake" {
-    eprintln!("Invalid handshake response");
-    return Err(Box::from("Invalid handshake response"));
-  }
-  if args.verbose {
-    eprintln!("Handshake successful!");
-  }
-
   // --- ここから非同期で送受信を分離 ---
   let (reader, writer) = stream.into_split();
   // 受信スレッドから送信スレッドへのチャネル
-  let (channel_tx, channel_rx) = mpsc::channel::<JDWPPacketDataFromDebuggee>(8192);
+  // 応答は packet id ごとの oneshot へ、イベントは専用のチャネルへ流す
+  let pending: PendingReplies = Arc::new(Mutex::new(HashMap::new()));
+  let (event_tx, event_rx) = mpsc::channel::<JDWPPacketDataFromDebuggee>(8192);
 
   // payload の保存用
   let payloads_recv = Arc::clone(&payloads);
   let context_recv = Arc::clone(&context);
   let payloads_send = Arc::clone(&payloads);
+  let pending_recv = Arc::clone(&pending);
 
   // 受信タスク
   let recv_task = tokio::spawn(handle_receive(
     reader,
     payloads_recv,
     context_recv,
-    channel_tx,
+    pending_recv,
+    event_tx,
   ));
 
   // 送信タスク
@@ -144,28 +242,96 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     writer,
     payloads_send,
     context,
-    channel_rx,
+    pending,
+    event_rx,
     args.verbose,
+    batch_file,
     args,
   ));
 
-  let (_recv_result, send_result) = tokio::try_join!(recv_task, send_task)?;
-  if send_result.is_err() {
-    eprintln!("Error in send task: {}", send_result.err().unwrap());
+  let (recv_result, send_result) = tokio::try_join!(recv_task, send_task)?;
+  let mut failed = false;
+  if let Err(e) = recv_result {
+    eprintln!("Error in receive task: {}", e);
+    failed = true;
+  }
+  if let Err(e) = send_result {
+    eprintln!("Error in send task: {}", e);
+    failed = true;
+  }
+  if failed {
+    std::process::exit(1);
   }
   Ok(())
 }
 
+async fn connect_and_handshake(
+  addr: &str,
+  verbose: bool,
+) -> Result<TcpStream, Box<dyn std::error::Error>> {
+  let mut stream = TcpStream::connect(addr).await?;
+  if verbose {
+    eprintln!("Connected to {}", addr);
+  }
+
+  // --- Handshake ---
+  let handshake = b"JDWP-Handshake";
+  stream.write_all(handshake).await?;
+  stream.flush().await?;
+  if verbose {
+    eprintln!("Sent handshake: {:?}", String::from_utf8_lossy(handshake));
+  }
+
+  // 応答を読む（同期的に一度読む）
+  let mut buf = [0u8; 14];
+  stream.read_exact(&mut buf).await?;
+  if &buf != b"JDWP-Handshake" {
+    return Err(Box::from("Invalid handshake response"));
+  }
+  if verbose {
+    eprintln!("Handshake successful!");
+  }
+
+  Ok(stream)
+}
+
 async fn handle_receive(
   mut reader: tokio::net::tcp::OwnedReadHalf,
   payloads: Arc<Mutex<Vec<JDWPPacketDataFromDebugger>>>,
   context: Arc<Mutex<JDWPContext>>,
-  channel_tx: mpsc::Sender<JDWPPacketDataFromDebuggee>,
-) {
+  pending: PendingReplies,
+  event_tx: mpsc::Sender<JDWPPacketDataFromDebuggee>,
+) -> Result<(), String> {
+  let result = receive_packets(&mut reader, &payloads, &context, &pending, &event_tx).await;
+
+  // 受信を終えたことを送信側に知らせる
+  drop(event_tx);
+  for (_, reply_tx) in pending.lock().await.drain() {
+    let _ = reply_tx.send(Err("connection closed".into()));
+  }
+
+  result
+}
+
+async fn receive_packets(
+  reader: &mut tokio::net::tcp::OwnedReadHalf,
+  payloads: &Mutex<Vec<JDWPPacketDataFromDebugger>>,
+  context: &Mutex<JDWPContext>,
+  pending: &PendingReplies,
+  event_tx: &mpsc::Sender<JDWPPacketDataFromDebuggee>,
+) -> Result<(), String> {
   while let Ok(length) = reader.read_u32().await {
+    // ヘッダ (length を除いて 7 バイト) に満たないパケットは壊れている
+    if length < 11 {
+      return Err(format!("Received packet with invalid length {}", length));
+    }
     let mut buf = vec![0u8; length as usize - 4];
 
-    let n = reader.read_exact(&mut buf).await.unwrap();
+    let n = reader
+      .read_exact(&mut buf)
+      .await
+      .map_err(|e| format!("Failed to read packet: {}", e))?;
+    let id = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
 
     // Await the async receive_packet function
     let packet_and_id = receive_packet(
@@ -183,7 +349,7 @@ async fn handle_receive(
       }
     }
 
-    if packet_and_id.is_err() {
+    let Ok((packet, _)) = packet_and_id else {
       eprint!("\n\nReceived packet: ");
       if n > 256 {
         eprint!("(too long to display) ");
@@ -193,458 +359,528 @@ async fn handle_receive(
         }
       }
       eprintln!();
-      let id = u32::from_be_bytes(buf[0..4].try_into().unwrap());
-      eprintln!("Send: {:?}", payloads.lock().await[id as usize]);
-      stderr().flush().unwrap();
-      panic!("Failed to decode packet")
-    }
-
-    let (packet, _) = packet_and_id.unwrap();
+      if let Some(payload) = payloads.lock().await.get(id as usize) {
+        eprintln!("Send: {:?}", payload);
+      }
+      let _ = stderr().flush();
+      return Err("Failed to decode packet".into());
+    };
 
-    channel_tx.send(packet).await.unwrap();
+    if let JDWPPacketDataFromDebuggee::EventComposite(event_composite) = &packet {
+      // VM が死んだら、応答待ちのリクエストをすべて失敗させる
+      if event_composite.events.iter().any(|event| {
+        matches!(
+          event.event_kind,
+          EventCompositeReceiveEventsEventKind::_VMDEATH(_)
+        )
+      }) {
+        for (_, reply_tx) in pending.lock().await.drain() {
+          let _ = reply_tx.send(Err("VM DEATH".into()));
+        }
+      }
+      // 停止待ちの後は誰もイベントを読まないので、バッファが埋まったら捨てる
+      // (send().await で待つと、以降の応答を振り分けられなくなる)
+      let _ = event_tx.try_send(packet);
+    } else {
+      // 応答ヘッダの id で、対応するリクエストに返す
+      if let Some(reply_tx) = pending.lock().await.remove(&id) {
+        let _ = reply_tx.send(Ok(packet));
+      }
+    }
   }
+  Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_send(
   writer: tokio::net::tcp::OwnedWriteHalf,
   payloads: Arc<Mutex<Vec<JDWPPacketDataFromDebugger>>>,
   context: Arc<Mutex<JDWPContext>>,
-  channel_rx: mpsc::Receiver<JDWPPacketDataFromDebuggee>,
+  pending: PendingReplies,
+  event_rx: mpsc::Receiver<JDWPPacketDataFromDebuggee>,
   verbose: bool,
+  batch_file: Option<tokio::fs::File>,
   args: Args,
 ) -> Result<(), String> {
   let Args {
     source_file,
     expression,
+    profile,
+    timeout_ms,
+    radix,
+    decimal,
     ..
   } = args;
 
-  let print_ln_what_is_doing = |what: &str| {
-    if verbose {
-      eprintln!("* {}..", what);
-    }
-  };
-  let print_what_is_doing = |what: &str| {
-    if verbose {
-      eprint!("* {}", what);
-    }
-  };
-  let print_done = || {
-    if verbose {
-      eprintln!("..OK!");
-    }
-  };
-  let print_info = |info: &str| {
-    if verbose {
-      eprintln!("* {}", info);
-    }
-  };
-
   let mut h = SendHandler {
     writer,
     payloads,
     context,
-    channel_rx,
+    pending,
+    event_rx,
     cmd_id: 0,
+    verbose,
+    timeout: Duration::from_millis(timeout_ms),
+    profiler: profile.then(profile::Profiler::default),
   };
 
-  print_what_is_doing("Get id sizes");
+  h.print_what_is_doing("Get id sizes");
   h.get_id_sizes().await?;
-  print_done();
+  h.print_done();
 
   // main() メソッドを待つ
-  print_what_is_doing("Set method entry breakpoint");
-  h.send_and_receive(&JDWPPacketDataFromDebugger::EventRequestSet(
-    EventRequestSetSend {
-      suspend_policy: 2,
-      modifiers: vec![EventRequestSetSendModifiers {
-        mod_kind: EventRequestSetSendModifiersModKind::_12(EventRequestSetSendModifiersModKind12 {
-          source_name_pattern: source_file.as_str().into(),
-        }),
-      }],
-      event_kind: 8, // PrepareClass
-    },
-  ))
-  .await?;
-  print_done();
-
-  // 最初の停止まで実行
-  print_what_is_doing("Resume VM");
-  h.send_and_receive(&JDWPPacketDataFromDebugger::VirtualMachineResume(()))
-    .await?;
-  print_done();
-
-  // 停止待ち
-  print_what_is_doing("Wait for breakpoint hit");
-  loop {
-    let packet = h.channel_rx.recv().await.unwrap();
-    if let JDWPPacketDataFromDebuggee::EventComposite(event_composite) = packet {
-      if event_composite.events.iter().any(|event| {
-        matches!(
-          event.event_kind,
-          EventCompositeReceiveEventsEventKind::_CLASSPREPARE(_)
-        )
-      }) {
-        break;
-      }
-    }
-  }
-  print_done();
-
-  // 現在のスレッドIDを取得する
-  print_what_is_doing("Find current thread");
-  let JDWPPacketDataFromDebuggee::VirtualMachineAllThreads(VirtualMachineAllThreadsReceive {
-    threads,
+  h.print_what_is_doing("Set method entry breakpoint");
+  let JDWPPacketDataFromDebuggee::EventRequestSet(EventRequestSetReceive {
+    request_id: class_prepare_request_id,
   }) = h
-    .send_and_receive(&JDWPPacketDataFromDebugger::VirtualMachineAllThreads(()))
+    .send_and_receive(&JDWPPacketDataFromDebugger::EventRequestSet(
+      EventRequestSetSend {
+        suspend_policy: 2,
+        modifiers: vec![EventRequestSetSendModifiers {
+          mod_kind: EventRequestSetSendModifiersModKind::_12(
+            EventRequestSetSendModifiersModKind12 {
+              source_name_pattern: source_file.as_str().into(),
+            },
+          ),
+        }],
+        event_kind: 8, // PrepareClass
+      },
+    ))
     .await?
   else {
-    panic!("Failed to get all threads")
+    panic!("Failed to set event request")
   };
-  let current_thread = threads.first().expect("No thread found").thread.clone();
-  print_done();
-  print_info(&format!("Current thread id: {}", current_thread));
-
-  // Class の id を問い合わせる
-  print_what_is_doing("Find java.lang.Class");
-  let clazz_of_class = h
-    .find_class("Ljava/lang/Class;")
-    .await
-    .expect("Failed to find Class class");
-  print_done();
-  // forName()
-  print_what_is_doing("Find Class.forName");
-  let method_class_for_name = h
-    .find_method(
-      &clazz_of_class,
-      "forName",
-      "(Ljava/lang/String;)Ljava/lang/Class;",
-    )
-    .await?;
-  print_done();
-  // getMethod()
-  print_what_is_doing("Find Class.getMethod");
-  let method_get_method = h
-    .find_method(
-      &clazz_of_class,
-      "getMethod",
-      "(Ljava/lang/String;[Ljava/lang/Class;)Ljava/lang/reflect/Method;",
-    )
-    .await?;
-  print_done();
-  // Long の id を得る
-  print_what_is_doing("Find java.lang.Long");
-  let clazz_long = h
-    .find_class("Ljava/lang/Long;")
-    .await
-    .expect("Failed to find Long class");
-  print_done();
-  // Long.valueOf(long) を得る
-  print_what_is_doing("Find Long.valueOf");
-  let method_long_value_of = h
-    .find_method(&clazz_long, "valueOf", "(J)Ljava/lang/Long;")
-    .await?;
-  print_done();
-  // java.lang.Long.TYPE フィールドの取得
-  print_what_is_doing("Find Long.TYPE");
-  let field_long_type = h
-    .find_field(&clazz_long, "TYPE", "Ljava/lang/Class;")
-    .await?;
-  print_done();
+  h.print_done();
 
-  // Long.TYPE フィールドの値を取得して Class オブジェクトを得る
-  print_what_is_doing("Get Long.TYPE value");
-  let class_long = {
-    let JDWPPacketDataFromDebuggee::ReferenceTypeGetValues(ReferenceTypeGetValuesReceive {
-      values,
+  // VM を動かした後は、途中で失敗しても後始末まで進むように 1 つのブロックにまとめる
+  let result: Result<(), String> = async {
+    // 最初の停止まで実行
+    h.print_what_is_doing("Resume VM");
+    h.send_and_receive(&JDWPPacketDataFromDebugger::VirtualMachineResume(()))
+      .await?;
+    h.print_done();
+
+    // 停止待ち
+    h.print_what_is_doing("Wait for breakpoint hit");
+    loop {
+      let packet = h.event_rx.recv().await.ok_or("Channel closed")?;
+      if let JDWPPacketDataFromDebuggee::EventComposite(event_composite) = packet {
+        if event_composite.events.iter().any(|event| {
+          matches!(
+            event.event_kind,
+            EventCompositeReceiveEventsEventKind::_CLASSPREPARE(_)
+          )
+        }) {
+          break;
+        }
+      }
+    }
+    h.print_done();
+
+    // 現在のスレッドIDを取得する
+    h.print_what_is_doing("Find current thread");
+    let JDWPPacketDataFromDebuggee::VirtualMachineAllThreads(VirtualMachineAllThreadsReceive {
+      threads,
     }) = h
-      .send_and_receive(&JDWPPacketDataFromDebugger::ReferenceTypeGetValues(
-        ReferenceTypeGetValuesSend {
-          ref_type: clazz_long.clone(),
-          fields: vec![ReferenceTypeGetValuesSendFields {
-            field_id: field_long_type.clone(),
-          }],
-        },
-      ))
+      .send_and_receive(&JDWPPacketDataFromDebugger::VirtualMachineAllThreads(()))
       .await?
     else {
-      panic!("Failed to get methods")
+      panic!("Failed to get all threads")
     };
-    match values
-      .first()
-      .ok_or("Failed to get Long.TYPE field value")?
-      .value
-      .clone()
-    {
-      JDWPValue::Object(obj_id) => obj_id,
-      JDWPValue::ClassObject(obj_id) => obj_id,
-      _ => return Err("Expected ClassObject value".into()),
-    }
-  };
-  print_done();
-
-  //Class.forName("java.math.BigInteger") を呼び出して BigInteger クラスのIDを得る
-  print_what_is_doing("Find java.math.BigInteger");
-  let string_big_integer = h.load_string("java.math.BigInteger").await.unwrap();
-  let class_big_integer = h
-    .invoke_class_method_return_object(
-      &clazz_of_class,
-      &method_class_for_name,
-      &current_thread,
-      &[JDWPValue::Object(string_big_integer)],
-    )
-    .await?;
-  print_done();
-
-  // 各メソッドのMethodインスタンスのメソッドIDを得る
-  print_what_is_doing("Find BigInteger.valueOf");
-  let value_of_method_instance = {
-    let name = h.load_string("valueOf").await?;
-    let arg = h
-      .create_jvm_array_from_jdwpvalues(
-        "[Ljava/lang/Class;",
-        vec![JDWPValue::ClassObject(class_long.clone())],
-      )
-      .await?;
-    h.invoke_object_method_return_object(
-      &clazz_of_class,
-      &class_big_integer.clone(),
-      &method_get_method,
-      &current_thread,
-      &[JDWPValue::String(name), JDWPValue::Array(arg)],
-    )
-    .await?
-  };
-  print_done();
-
-  print_what_is_doing("Find BigInteger add methods");
-  let add_method_instance = {
-    let name = h.load_string("add").await?;
-    let arg = h
-      .create_jvm_array_from_jdwpvalues(
-        "[Ljava/lang/Class;",
-        vec![JDWPValue::ClassObject(class_big_integer.clone())],
-      )
-      .await?;
-    h.invoke_object_method_return_object(
-      &clazz_of_class,
-      &class_big_integer.clone(),
-      &method_get_method,
-      &current_thread,
-      &[JDWPValue::String(name), JDWPValue::Array(arg)],
-    )
-    .await?
-  };
-  print_done();
-
-  print_what_is_doing("Find BigInteger subtract methods");
-  let subtract_method_instance = {
-    let name = h.load_string("subtract").await?;
-    let arg = h
-      .create_jvm_array_from_jdwpvalues(
-        "[Ljava/lang/Class;",
-        vec![JDWPValue::ClassObject(class_big_integer.clone())],
-      )
-      .await?;
-    h.invoke_object_method_return_object(
-      &clazz_of_class,
-      &class_big_integer.clone(),
-      &method_get_method,
-      &current_thread,
-      &[JDWPValue::String(name), JDWPValue::Array(arg)],
-    )
-    .await?
-  };
-  print_done();
-
-  print_what_is_doing("Find BigInteger multiply methods");
-  let multiply_method_instance = {
-    let name = h.load_string("multiply").await?;
-    let arg = h
-      .create_jvm_array_from_jdwpvalues(
-        "[Ljava/lang/Class;",
-        vec![JDWPValue::ClassObject(class_big_integer.clone())],
-      )
-      .await?;
-    h.invoke_object_method_return_object(
-      &clazz_of_class,
-      &class_big_integer.clone(),
-      &method_get_method,
-      &current_thread,
-      &[JDWPValue::String(name), JDWPValue::Array(arg)],
-    )
-    .await?
-  };
-  print_done();
-
-  print_what_is_doing("Find BigInteger divide methods");
-  let divide_method_instance = {
-    let name = h.load_string("divide").await?;
-    let arg = h
-      .create_jvm_array_from_jdwpvalues(
-        "[Ljava/lang/Class;",
-        vec![JDWPValue::ClassObject(class_big_integer.clone())],
+    let current_thread = threads.first().expect("No thread found").thread.clone();
+    h.print_done();
+    h.print_info(&format!("Current thread id: {}", current_thread));
+
+    // Class の id を問い合わせる
+    h.print_what_is_doing("Find java.lang.Class");
+    let clazz_of_class = h
+      .find_class("Ljava/lang/Class;")
+      .await
+      .expect("Failed to find Class class");
+    h.print_done();
+    // forName()
+    h.print_what_is_doing("Find Class.forName");
+    let method_class_for_name = h
+      .find_method(
+        &clazz_of_class,
+        "forName",
+        "(Ljava/lang/String;)Ljava/lang/Class;",
       )
       .await?;
-    h.invoke_object_method_return_object(
-      &clazz_of_class,
-      &class_big_integer.clone(),
-      &method_get_method,
-      &current_thread,
-      &[JDWPValue::String(name), JDWPValue::Array(arg)],
-    )
-    .await?
-  };
-  print_done();
+    h.print_done();
 
-  print_what_is_doing("Find BigInteger toString methods");
-  let to_string_method_instance = {
-    let name = h.load_string("toString").await?;
-    h.invoke_object_method_return_object(
+    // 計算に使うクラス (--decimal 時は BigDecimal、そうでなければ BigInteger)
+    let number_class_name = if decimal { "BigDecimal" } else { "BigInteger" };
+    let number_signature = format!("Ljava/math/{};", number_class_name);
+
+    //Class.forName("java.math.BigInteger") でクラスをロードしてから、その id を得る
+    h.print_what_is_doing(&format!("Find java.math.{}", number_class_name));
+    let string_number = h
+      .load_string(&format!("java.math.{}", number_class_name))
+      .await?;
+    h.invoke_class_method_return_object(
       &clazz_of_class,
-      &class_big_integer.clone(),
-      &method_get_method,
+      &method_class_for_name,
       &current_thread,
-      &[
-        JDWPValue::String(name),
-        JDWPValue::Array(
-          JDWPIDLengthEqObject::from_value(&vec![PrettyIOKind::Int(0)])
-            .unwrap()
-            .0,
-        ),
-      ],
-    )
-    .await?
-  };
-  print_done();
-
-  // Method クラスを得る
-  print_what_is_doing("Find java.lang.reflect.Method");
-  let clazz_method = h.find_class("Ljava/lang/reflect/Method;").await?;
-  print_done();
-
-  print_what_is_doing("Find Method.invoke");
-  let invoke_method = h
-    .find_method(
-      &clazz_method,
-      "invoke",
-      "(Ljava/lang/Object;[Ljava/lang/Object;)Ljava/lang/Object;",
+      &[JDWPValue::Object(string_number)],
     )
     .await?;
-  print_done();
-
-  let mut input = String::new();
-  let mut stdin = tokio::io::BufReader::new(tokio::io::stdin());
-
-  if let Some(ref expr) = expression {
-    match h
-      .calc_expression(
-        expr,
-        &clazz_long,
-        &method_long_value_of,
-        &clazz_method,
-        &value_of_method_instance,
-        &add_method_instance,
-        &subtract_method_instance,
-        &multiply_method_instance,
-        &divide_method_instance,
-        &to_string_method_instance,
-        &invoke_method,
+    let clazz_number = h.find_class(&number_signature).await?;
+    h.print_done();
+
+    // 割り切れない除算でも例外にならないよう、--decimal 時は MathContext.DECIMAL128 で丸める
+    let math_context = if decimal {
+      h.print_what_is_doing("Find MathContext.DECIMAL128");
+      let string_math_context = h.load_string("java.math.MathContext").await?;
+      h.invoke_class_method_return_object(
+        &clazz_of_class,
+        &method_class_for_name,
         &current_thread,
-        &Box::new(print_what_is_doing),
-        &Box::new(print_ln_what_is_doing),
-        &Box::new(print_done),
+        &[JDWPValue::Object(string_math_context)],
       )
-      .await
-    {
-      Ok(result) => {
-        print!("{}", result);
-      }
-      Err(e) => {
-        return Err(format!("Parse error: {}", e));
-      }
-    }
-  } else if atty::is(atty::Stream::Stdin) {
-    loop {
-      print!("jcalc> ");
-      stdout().flush().unwrap();
-      stdin.read_line(&mut input).await.unwrap();
-      if input.trim() == "exit" {
-        break;
-      }
+      .await?;
+      let clazz_math_context = h.find_class("Ljava/math/MathContext;").await?;
+      let field_decimal128 = h
+        .find_field(&clazz_math_context, "DECIMAL128", "Ljava/math/MathContext;")
+        .await?;
+      let math_context = h
+        .get_static_object_field(&clazz_math_context, &field_decimal128)
+        .await?;
+      h.print_done();
+      Some(math_context)
+    } else {
+      None
+    };
 
-      match h
-        .calc_expression(
-          &input,
-          &clazz_long,
-          &method_long_value_of,
-          &clazz_method,
-          &value_of_method_instance,
-          &add_method_instance,
-          &subtract_method_instance,
-          &multiply_method_instance,
-          &divide_method_instance,
-          &to_string_method_instance,
-          &invoke_method,
-          &current_thread,
-          &Box::new(print_what_is_doing),
-          &Box::new(print_ln_what_is_doing),
-          &Box::new(print_done),
+    // 各メソッドのメソッドIDを得る
+    // BigInteger には mod と remainder があるが、結果が常に非負になる mod を使う
+    // (除数が 0 以下なら、0 でも負でも ArithmeticException が送出される)
+    // BigDecimal には mod が無いので remainder を使う
+    h.print_what_is_doing(&format!("Find {} methods", number_class_name));
+    let binary_signature = format!("({0}){0}", number_signature);
+    let number_methods = NumberMethods {
+      class_name: number_class_name,
+      value_of: h
+        .find_method(
+          &clazz_number,
+          "valueOf",
+          &format!("(J){}", number_signature),
+        )
+        .await?,
+      // 小数リテラルは new BigDecimal(String) で精度を落とさずに作る
+      constructor_string: if decimal {
+        Some(
+          h.find_method(&clazz_number, "<init>", "(Ljava/lang/String;)V")
+            .await?,
+        )
+      } else {
+        None
+      },
+      add: h
+        .find_method(&clazz_number, "add", &binary_signature)
+        .await?,
+      subtract: h
+        .find_method(&clazz_number, "subtract", &binary_signature)
+        .await?,
+      multiply: h
+        .find_method(&clazz_number, "multiply", &binary_signature)
+        .await?,
+      divide: if math_context.is_some() {
+        h.find_method(
+          &clazz_number,
+          "divide",
+          &format!("({0}Ljava/math/MathContext;){0}", number_signature),
+        )
+        .await?
+      } else {
+        h.find_method(&clazz_number, "divide", &binary_signature)
+          .await?
+      },
+      modulo: h
+        .find_method(
+          &clazz_number,
+          if decimal { "remainder" } else { "mod" },
+          &binary_signature,
+        )
+        .await?,
+      negate: h
+        .find_method(&clazz_number, "negate", &format!("(){}", number_signature))
+        .await?,
+      pow: h
+        .find_method(&clazz_number, "pow", &format!("(I){}", number_signature))
+        .await?,
+      // 結果の末尾の 0 (DECIMAL128 の除算で付く最大 34 桁ぶん) を落とす
+      strip_trailing_zeros: if decimal {
+        Some(
+          h.find_method(
+            &clazz_number,
+            "stripTrailingZeros",
+            &format!("(){}", number_signature),
+          )
+          .await?,
         )
+      } else {
+        None
+      },
+      // --radix 指定時は toString(int) を、そうでなければ toString() を使う
+      // BigDecimal.toString() は 1E-7 のような指数表記になるので、toPlainString() を使う
+      to_string: h
+        .find_method(
+          &clazz_number,
+          if decimal { "toPlainString" } else { "toString" },
+          if radix.is_some() {
+            "(I)Ljava/lang/String;"
+          } else {
+            "()Ljava/lang/String;"
+          },
+        )
+        .await?,
+      clazz: clazz_number,
+      radix,
+      math_context,
+    };
+    h.print_done();
+
+    // REPL で束縛された変数
+    let mut variables: HashMap<String, JDWPIDLengthEqObject> = HashMap::new();
+
+    let mut input = String::new();
+    let mut stdin = tokio::io::BufReader::new(tokio::io::stdin());
+
+    if let Some(ref expr) = expression {
+      match h
+        .calc_expression(expr, &mut variables, &number_methods, &current_thread)
         .await
       {
         Ok(result) => {
-          println!("{}", result);
+          print!("{}", result);
+          Ok(())
         }
-        Err(e) => {
-          eprintln!("Parse error: {}", e);
+        Err(e) => Err(format!("Parse error: {}", e)),
+      }
+    } else if batch_file.is_none() && atty::is(atty::Stream::Stdin) {
+      loop {
+        print!("jcalc> ");
+        stdout().flush().unwrap();
+        input.clear();
+        // EOF (Ctrl-D) でも終了する
+        if stdin.read_line(&mut input).await.unwrap_or(0) == 0 || input.trim() == "exit" {
+          break Ok(());
+        }
+
+        match h
+          .calc_expression(&input, &mut variables, &number_methods, &current_thread)
+          .await
+        {
+          Ok(result) => {
+            println!("{}", result);
+          }
+          // 接続が失われたら、これ以上計算できないので終了する
+          Err(e) if h.is_connection_lost(&e) => {
+            break Err(e);
+          }
+          Err(e) => {
+            eprintln!("Parse error: {}", e);
+          }
         }
       }
-    }
-  } else {
-    let mut expr: String = String::new();
-    stdin.read_line(&mut expr).await.unwrap();
-
-    match h
-      .calc_expression(
-        &expr,
-        &clazz_long,
-        &method_long_value_of,
-        &clazz_method,
-        &value_of_method_instance,
-        &add_method_instance,
-        &subtract_method_instance,
-        &multiply_method_instance,
-        &divide_method_instance,
-        &to_string_method_instance,
-        &invoke_method,
-        &current_thread,
-        &Box::new(print_what_is_doing),
-        &Box::new(print_ln_what_is_doing),
-        &Box::new(print_done),
-      )
-      .await
-    {
-      Ok(result) => {
-        print!("{}", result);
+    } else {
+      // バッチモード: 1 行ずつ評価し、失敗した行があれば最後にエラーにする
+      let reader: Box<dyn AsyncBufRead + Unpin + Send> = match batch_file {
+        Some(file) => Box::new(tokio::io::BufReader::new(file)),
+        None => Box::new(stdin),
+      };
+      let mut lines = reader.lines();
+      let mut line_no = 0;
+      let mut failed = 0;
+      let mut fatal_error = None;
+      loop {
+        let line = match lines.next_line().await {
+          Ok(Some(line)) => line,
+          Ok(None) => break,
+          Err(e) => {
+            fatal_error = Some(format!("Failed to read input: {}", e));
+            break;
+          }
+        };
+        line_no += 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+          continue;
+        }
+
+        match h
+          .calc_expression(line, &mut variables, &number_methods, &current_thread)
+          .await
+        {
+          Ok(result) => {
+            println!("{}", result);
+          }
+          Err(e) if h.is_connection_lost(&e) => {
+            fatal_error = Some(format!("Line {}: {}", line_no, e));
+            break;
+          }
+          Err(e) => {
+            eprintln!("Line {}: Parse error: {}", line_no, e);
+            failed += 1;
+          }
+        }
       }
-      Err(e) => {
-        return Err(format!("Parse error: {}", e));
+
+      if let Some(e) = fatal_error {
+        Err(e)
+      } else if failed > 0 {
+        Err(format!("{} of the input lines failed", failed))
+      } else {
+        Ok(())
       }
     }
   }
-  Ok(())
+  .await;
+
+  // イベントリクエストを外して VM を解放する
+  // (exit や EOF だけでなくエラーで抜けた場合も行い、失敗しても既に出した結果やエラーは変えない)
+  h.print_what_is_doing("Dispose VM");
+  let teardown = async {
+    if h.event_rx.is_closed() {
+      return Err("connection closed".to_string());
+    }
+    h.send_and_receive(&JDWPPacketDataFromDebugger::EventRequestClear(
+      EventRequestClearSend {
+        event_kind: 8, // PrepareClass
+        request_id: class_prepare_request_id,
+      },
+    ))
+    .await?;
+    h.send_and_receive(&JDWPPacketDataFromDebugger::VirtualMachineDispose(()))
+      .await
+  }
+  .await;
+  match teardown {
+    Ok(_) => h.print_done(),
+    Err(e) => eprintln!("Failed to dispose VM: {}", e),
+  }
+
+  if let Some(profiler) = &h.profiler {
+    eprintln!();
+    eprint!("{}", profiler.report());
+  }
+
+  result
+}
+
+// 計算に使うクラス (BigInteger か BigDecimal) のメソッド
+struct NumberMethods {
+  class_name: &'static str,
+  clazz: JDWPIDLengthEqReferenceType,
+  value_of: JDWPIDLengthEqMethod,
+  constructor_string: Option<JDWPIDLengthEqMethod>,
+  add: JDWPIDLengthEqMethod,
+  subtract: JDWPIDLengthEqMethod,
+  multiply: JDWPIDLengthEqMethod,
+  divide: JDWPIDLengthEqMethod,
+  modulo: JDWPIDLengthEqMethod,
+  negate: JDWPIDLengthEqMethod,
+  pow: JDWPIDLengthEqMethod,
+  strip_trailing_zeros: Option<JDWPIDLengthEqMethod>,
+  to_string: JDWPIDLengthEqMethod,
+  radix: Option<u32>,
+  // --decimal 時に divide へ渡す MathContext
+  math_context: Option<JDWPIDLengthEqObject>,
+}
+
+// BigDecimal.pow は 999999999 を超える指数で ArithmeticException を投げる
+const DECIMAL_MAX_EXPONENT: i32 = 999_999_999;
+// BigInteger は 2^Integer.MAX_VALUE 未満しか表せないので、底が 2 でも収まる指数までに抑える
+// (それより大きい底で範囲を超えた場合は、JVM 側の例外になる)
+const INTEGER_MAX_EXPONENT: i32 = i32::MAX - 1;
+
+// pow を呼ぶ前に、指数が上限に収まっているか確かめる
+fn check_exponent(exponent: i32, decimal: bool) -> Result<(), String> {
+  let max = if decimal {
+    DECIMAL_MAX_EXPONENT
+  } else {
+    INTEGER_MAX_EXPONENT
+  };
+  if exponent > max {
+    Err(format!("Exponent too large (max {})", max))
+  } else {
+    Ok(())
+  }
+}
+
+// 変数を束縛し直す。もうどの変数からも参照されなくなった古い値があれば返す
+// (DisableCollection は参照カウントされないので、他の変数が同じ値を持つ間は戻してはいけない)
+fn rebind_variable(
+  variables: &mut HashMap<String, JDWPIDLengthEqObject>,
+  name: String,
+  value: JDWPIDLengthEqObject,
+) -> Option<JDWPIDLengthEqObject> {
+  let old = variables.insert(name, value)?;
+  if variables.values().any(|v| v.id == old.id) {
+    None
+  } else {
+    Some(old)
+  }
 }
 
+// packet id ごとの応答待ち
+type PendingReplies =
+  Arc<Mutex<HashMap<u32, oneshot::Sender<Result<JDWPPacketDataFromDebuggee, String>>>>>;
+
 struct SendHandler {
   writer: tokio::net::tcp::OwnedWriteHalf,
   payloads: Arc<Mutex<Vec<JDWPPacketDataFromDebugger>>>,
   context: Arc<Mutex<JDWPContext>>,
-  channel_rx: mpsc::Receiver<JDWPPacketDataFromDebuggee>,
+  pending: PendingReplies,
+  event_rx: mpsc::Receiver<JDWPPacketDataFromDebuggee>,
   cmd_id: i32,
+  verbose: bool,
+  timeout: Duration,
+  profiler: Option<profile::Profiler>,
 }
 
 impl SendHandler {
+  // --verbose 時の進捗表示 (いずれも stderr へ)
+  fn print_ln_what_is_doing(&self, what: &str) {
+    if self.verbose {
+      eprintln!("* {}..", what);
+    }
+  }
+
+  fn print_what_is_doing(&self, what: &str) {
+    if self.verbose {
+      eprint!("* {}", what);
+    }
+  }
+
+  fn print_done(&self) {
+    if self.verbose {
+      eprintln!("..OK!");
+    }
+  }
+
+  fn print_info(&self, info: &str) {
+    if self.verbose {
+      eprintln!("* {}", info);
+    }
+  }
+
+  // 受信タスクが終わった (VM が死んだ) 後のエラーかどうか
+  fn is_connection_lost(&self, e: &str) -> bool {
+    self.event_rx.is_closed() || e == "connection closed" || e == "VM DEATH"
+  }
+
+  fn print_packet(
+    &self,
+    payload: &JDWPPacketDataFromDebugger,
+    response_packet: &JDWPPacketDataFromDebuggee,
+  ) {
+    if self.verbose {
+      eprintln!("! {:?} -> {:?}", payload, response_packet);
+    }
+  }
+
   async fn send_and_receive(
     &mut self,
     payload: &JDWPPacketDataFromDebugger,
@@ -652,36 +888,50 @@ impl SendHandler {
     // Clone the payload to avoid borrowing issues
     let payload_clone = payload.clone();
 
+    let started = Instant::now();
+
+    // 送信前に応答の受け口を登録しておく
+    let id = self.cmd_id as u32;
+    let (reply_tx, reply_rx) = oneshot::channel();
+    self.pending.lock().await.insert(id, reply_tx);
+    // 受信タスクが既に終わっていれば、応答は来ない
+    if self.event_rx.is_closed() {
+      self.pending.lock().await.remove(&id);
+      return Err("connection closed".into());
+    }
+
     // Send the packet synchronously using block_on or similar approach
     {
       self.payloads.lock().await.push(payload_clone.clone());
-      send_packet(&mut self.writer, self.cmd_id, &payload_clone)
-        .await
-        .unwrap();
+      let sent = send_packet(&mut self.writer, self.cmd_id, &payload_clone).await;
       self.cmd_id += 1;
+      if sent.is_err() {
+        self.pending.lock().await.remove(&id);
+        return Err("connection closed".into());
+      }
     }
 
-    loop {
-      match self.channel_rx.recv().await {
-        Some(JDWPPacketDataFromDebuggee::EventComposite(event_composite)) => {
-          if event_composite.events.iter().any(|event| {
-            matches!(
-              event.event_kind,
-              EventCompositeReceiveEventsEventKind::_VMDEATH(_)
-            )
-          }) {
-            return Err("VM DEATH".into());
-          }
-        }
-        Some(response_packet) => {
-          println!("! {:?} -> {:?}", payload_clone, response_packet);
-          return Ok(response_packet);
-        }
-        None => {
-          return Err("Channel closed".into());
+    let response_packet = match tokio::time::timeout(self.timeout, reply_rx).await {
+      Ok(reply) => reply.map_err(|_| "connection closed".to_string())??,
+      Err(_) => {
+        // 遅れて届いた応答は捨てられるよう、受け口を外しておく
+        self.pending.lock().await.remove(&id);
+        if let Some(profiler) = &mut self.profiler {
+          profiler.record_timeout(&payload_clone, started.elapsed());
         }
+        return Err(format!(
+          "request timed out after {} ms",
+          self.timeout.as_millis()
+        ));
       }
+    };
+    // --verbose 時のパケット表示にかかる時間は含めない
+    let elapsed = started.elapsed();
+    self.print_packet(&payload_clone, &response_packet);
+    if let Some(profiler) = &mut self.profiler {
+      profiler.record(&payload_clone, elapsed);
     }
+    Ok(response_packet)
   }
 
   async fn get_id_sizes(&mut self) -> Result<(), String> {
@@ -784,6 +1034,39 @@ impl SendHandler {
     Err(format!("Field {} not found", field_name))
   }
 
+  // static フィールドに入っているオブジェクトを得る
+  async fn get_static_object_field(
+    &mut self,
+    clazz: &JDWPIDLengthEqReferenceType,
+    field_id: &JDWPIDLengthEqField,
+  ) -> Result<JDWPIDLengthEqObject, String> {
+    let JDWPPacketDataFromDebuggee::ReferenceTypeGetValues(ReferenceTypeGetValuesReceive {
+      values,
+    }) = self
+      .send_and_receive(&JDWPPacketDataFromDebugger::ReferenceTypeGetValues(
+        ReferenceTypeGetValuesSend {
+          ref_type: clazz.clone(),
+          fields: vec![ReferenceTypeGetValuesSendFields {
+            field_id: field_id.clone(),
+          }],
+        },
+      ))
+      .await?
+    else {
+      panic!("Failed to get methods")
+    };
+    match values
+      .first()
+      .ok_or("Failed to get field value")?
+      .value
+      .clone()
+    {
+      JDWPValue::Object(obj_id) => Ok(obj_id),
+      JDWPValue::ClassObject(obj_id) => Ok(obj_id),
+      _ => Err("Expected ClassObject value".into()),
+    }
+  }
+
   async fn invoke_class_method_return_object(
     &mut self,
     clazz: &JDWPIDLengthEqReferenceType,
@@ -793,7 +1076,7 @@ impl SendHandler {
   ) -> Result<JDWPIDLengthEqObject, String> {
     let JDWPPacketDataFromDebuggee::ClassTypeInvokeMethod(ClassTypeInvokeMethodReceive {
       return_value,
-      exception: _exception,
+      exception,
     }) = self
       .send_and_receive(&JDWPPacketDataFromDebugger::ClassTypeInvokeMethod(
         ClassTypeInvokeMethodSend {
@@ -812,6 +1095,20 @@ impl SendHandler {
       panic!("Failed to invoke method")
     };
 
+    if exception.object_id != 0 {
+      return Err(format!(
+        "Method invocation threw an exception {}",
+        self
+          .get_exception_string(
+            &JDWPIDLengthEqObject {
+              id: exception.object_id
+            },
+            thread
+          )
+          .await?,
+      ));
+    }
+
     match return_value {
       JDWPValue::Object(obj_id) => Ok(obj_id),
       JDWPValue::ClassObject(obj_id) => Ok(obj_id),
@@ -819,6 +1116,53 @@ impl SendHandler {
     }
   }
 
+  async fn new_instance(
+    &mut self,
+    clazz: &JDWPIDLengthEqReferenceType,
+    constructor_id: &JDWPIDLengthEqMethod,
+    thread: &JDWPIDLengthEqObject,
+    args: &[JDWPValue],
+  ) -> Result<JDWPIDLengthEqObject, String> {
+    let JDWPPacketDataFromDebuggee::ClassTypeNewInstance(ClassTypeNewInstanceReceive {
+      new_object,
+      exception,
+    }) = self
+      .send_and_receive(&JDWPPacketDataFromDebugger::ClassTypeNewInstance(
+        ClassTypeNewInstanceSend {
+          clazz: clazz.clone(),
+          thread: thread.clone(),
+          method_id: constructor_id.clone(),
+          arguments: args
+            .iter()
+            .map(|arg| ClassTypeNewInstanceSendArguments { arg: arg.clone() })
+            .collect(),
+          options: 0,
+        },
+      ))
+      .await?
+    else {
+      panic!("Failed to create instance")
+    };
+
+    if exception.object_id != 0 {
+      return Err(format!(
+        "Constructor threw an exception {}",
+        self
+          .get_exception_string(
+            &JDWPIDLengthEqObject {
+              id: exception.object_id
+            },
+            thread
+          )
+          .await?,
+      ));
+    }
+
+    Ok(JDWPIDLengthEqObject {
+      id: new_object.object_id,
+    })
+  }
+
   async fn invoke_object_method_return_object(
     &mut self,
     clazz: &JDWPIDLengthEqReferenceType,
@@ -874,59 +1218,17 @@ impl SendHandler {
     }
   }
 
-  // jdwpvalue の配列を、JVmP の配列オブジェクトに変換するユーティリティ関数
-  async fn create_jvm_array_from_jdwpvalues(
-    &mut self,
-    elements_signature: &str,
-    values: Vec<JDWPValue>,
-  ) -> Result<JDWPIDLengthEqObject, String> {
-    // まず配列のリファレンスを取る
-    let clazz_array = self.find_class(elements_signature).await?;
-
-    // 配列オブジェクトの作成
-    let JDWPPacketDataFromDebuggee::ArrayTypeNewInstance(ArrayTypeNewInstanceReceive { new_array }) =
-      self
-        .send_and_receive(&JDWPPacketDataFromDebugger::ArrayTypeNewInstance(
-          ArrayTypeNewInstanceSend {
-            arr_type: clazz_array.clone(),
-            length: values.len() as i32,
-          },
-        ))
-        .await?
-    else {
-      panic!("Failed to create array")
-    };
-    let new_array_untagged = JDWPIDLengthEqObject {
-      id: new_array.object_id,
-    };
-
-    // 配列への値の設定
-    self
-      .send_and_receive(&JDWPPacketDataFromDebugger::ArrayReferenceSetValues(
-        ArrayReferenceSetValuesSend {
-          array_object: new_array_untagged.clone(),
-          first_index: 0,
-          values: values
-            .into_iter()
-            .map(|v| ArrayReferenceSetValuesSendValues { value: v })
-            .collect(),
-        },
-      ))
-      .await?;
-
-    Ok(new_array_untagged)
-  }
-
   async fn get_exception_string(
     &mut self,
     exception: &JDWPIDLengthEqObject,
     thread: &JDWPIDLengthEqObject,
   ) -> Result<String, String> {
     let th = self.find_class("Ljava/lang/Throwable;").await?;
-    let get_message_method = self
-      .find_method(&th, "getMessage", "()Ljava/lang/String;")
+    let to_string_method = self
+      .find_method(&th, "toString", "()Ljava/lang/String;")
       .await?;
 
+    // getMessage() は null になり得るので toString() を使う
     let JDWPPacketDataFromDebuggee::ObjectReferenceInvokeMethod(
       ObjectReferenceInvokeMethodReceive {
         return_value: JDWPValue::String(return_value),
@@ -938,7 +1240,7 @@ impl SendHandler {
           object: exception.clone(),
           clazz: th.clone(),
           thread: thread.clone(),
-          method_id: get_message_method.clone(),
+          method_id: to_string_method,
           arguments: vec![],
           options: 0,
         },
@@ -967,27 +1269,16 @@ impl SendHandler {
     Ok(msg_str)
   }
 
-  #[allow(clippy::too_many_arguments)]
   async fn calc_expression(
     &mut self,
     expr: &str,
-    clazz_long: &JDWPIDLengthEqReferenceType,
-    method_long_value_of: &JDWPIDLengthEqMethod,
-    clazz_method: &JDWPIDLengthEqReferenceType,
-    value_of_method_instance: &JDWPIDLengthEqObject,
-    add_method_instance: &JDWPIDLengthEqObject,
-    subtract_method_instance: &JDWPIDLengthEqObject,
-    multiply_method_instance: &JDWPIDLengthEqObject,
-    divide_method_instance: &JDWPIDLengthEqObject,
-    to_string_method_instance: &JDWPIDLengthEqObject,
-    invoke_method: &JDWPIDLengthEqMethod,
+    variables: &mut HashMap<String, JDWPIDLengthEqObject>,
+    methods: &NumberMethods,
     current_thread: &JDWPIDLengthEqObject,
-
-    print_what_is_doing: impl Fn(&str),
-    print_ln_what_is_doing: impl Fn(&str),
-    print_done: impl Fn(),
   ) -> Result<String, String> {
     let h = self;
+    let started = Instant::now();
+    let first_cmd_id = h.cmd_id;
 
     match parse::parse_input(expr) {
       Ok(exprs) => {
@@ -995,109 +1286,164 @@ impl SendHandler {
         for expr in exprs {
           match expr {
             parse::Expression::Number(n) => {
-              print_what_is_doing(&format!("Constructing Long from {}", n));
-              let long_obj = h
-                .invoke_class_method_return_object(
-                  clazz_long,
-                  method_long_value_of,
+              h.print_what_is_doing(&format!("Invoking {}.valueOf({})", methods.class_name, n));
+              stack.push(
+                h.invoke_class_method_return_object(
+                  &methods.clazz,
+                  &methods.value_of,
                   current_thread,
                   &[JDWPValue::Long(n)],
                 )
-                .await?;
-              print_done();
-
-              print_what_is_doing("Creating JVM array for Long to invoke BigInteger.valueOf");
-              let arg = h
-                .create_jvm_array_from_jdwpvalues(
-                  "[Ljava/lang/Object;",
-                  vec![JDWPValue::Object(long_obj.clone())],
-                )
-                .await?;
-              print_done();
-
-              print_what_is_doing("Invoking BigInteger.valueOf");
+                .await?,
+              );
+              h.print_done();
+            }
+            parse::Expression::Decimal(d) => {
+              let constructor_string = methods
+                .constructor_string
+                .as_ref()
+                .ok_or("Decimal literals require --decimal")?;
+              h.print_what_is_doing(&format!("Invoking new {}(\"{}\")", methods.class_name, d));
+              let literal = h.load_string(&d).await?;
               stack.push(
-                h.invoke_object_method_return_object(
-                  &clazz_method.clone(),
-                  &value_of_method_instance.clone(),
-                  &invoke_method.clone(),
+                h.new_instance(
+                  &methods.clazz,
+                  constructor_string,
                   current_thread,
-                  &[
-                    JDWPValue::Object(
-                      JDWPIDLengthEqObject::from_value(&vec![PrettyIOKind::Int(0)])
-                        .unwrap()
-                        .0,
-                    ),
-                    JDWPValue::Array(arg),
-                  ],
+                  &[JDWPValue::String(literal)],
                 )
                 .await?,
               );
-              print_done();
+              h.print_done();
             }
-            parse::Expression::Binary(op) => {
-              let b = stack.pop().expect("Stack underflow");
+            parse::Expression::Variable(name) => {
+              let value = variables
+                .get(&name)
+                .ok_or(format!("Undefined variable '{}'", name))?;
+              stack.push(value.clone());
+            }
+            parse::Expression::Assign(name) => {
+              let value = stack.last().expect("Stack underflow").clone();
+              // 束縛した値が GC で回収されないようにする
+              h.print_what_is_doing(&format!("Binding {} to {}", name, value));
+              h.send_and_receive(
+                &JDWPPacketDataFromDebugger::ObjectReferenceDisableCollection(
+                  ObjectReferenceDisableCollectionSend {
+                    object: value.clone(),
+                  },
+                ),
+              )
+              .await?;
+              if let Some(old) = rebind_variable(variables, name, value) {
+                h.send_and_receive(
+                  &JDWPPacketDataFromDebugger::ObjectReferenceEnableCollection(
+                    ObjectReferenceEnableCollectionSend { object: old },
+                  ),
+                )
+                .await?;
+              }
+              h.print_done();
+            }
+            parse::Expression::Unary(op) => {
               let a = stack.pop().expect("Stack underflow");
-              print_ln_what_is_doing(&format!("Calc binary expression: {} {:?} {}", a, op, b));
-              let op_method_instance = {
+              let op_method = {
                 match op {
-                  parse::Operator::Add => add_method_instance.clone(),
-                  parse::Operator::Subtract => subtract_method_instance.clone(),
-                  parse::Operator::Multiply => multiply_method_instance.clone(),
-                  parse::Operator::Divide => divide_method_instance.clone(),
+                  parse::UnaryOperator::Negate => &methods.negate,
                 }
               };
-              print_what_is_doing(&format!(
-                "Creating JVM array for BigInteger operation {:?}",
-                op
-              ));
-              let varargs = h
-                .create_jvm_array_from_jdwpvalues(
-                  "[Ljava/lang/Object;",
-                  vec![JDWPValue::Object(b.clone())],
+              h.print_what_is_doing(&format!("Calc unary expression: {:?} {}", op, a));
+              let result = h
+                .invoke_object_method_return_object(
+                  &methods.clazz,
+                  &a,
+                  op_method,
+                  current_thread,
+                  &[],
                 )
                 .await?;
-              print_done();
-
-              print_what_is_doing(&format!("Invoke: {:?}", op_method_instance));
+              stack.push(result);
+              h.print_done();
+            }
+            parse::Expression::Power(exponent) => {
+              check_exponent(exponent, methods.math_context.is_some())?;
+              let a = stack.pop().expect("Stack underflow");
+              h.print_what_is_doing(&format!("Calc power expression: {} ^ {}", a, exponent));
+              let result = h
+                .invoke_object_method_return_object(
+                  &methods.clazz,
+                  &a,
+                  &methods.pow,
+                  current_thread,
+                  &[JDWPValue::Int(exponent)],
+                )
+                .await?;
+              stack.push(result);
+              h.print_done();
+            }
+            parse::Expression::Binary(op) => {
+              let b = stack.pop().expect("Stack underflow");
+              let a = stack.pop().expect("Stack underflow");
+              let op_method = {
+                match op {
+                  parse::Operator::Add => &methods.add,
+                  parse::Operator::Subtract => &methods.subtract,
+                  parse::Operator::Multiply => &methods.multiply,
+                  parse::Operator::Divide => &methods.divide,
+                  parse::Operator::Modulo => &methods.modulo,
+                }
+              };
+              let mut args = vec![JDWPValue::Object(b.clone())];
+              // BigDecimal の divide には MathContext も渡す
+              if let (parse::Operator::Divide, Some(math_context)) = (&op, &methods.math_context) {
+                args.push(JDWPValue::Object(math_context.clone()));
+              }
+              h.print_what_is_doing(&format!("Calc binary expression: {} {:?} {}", a, op, b));
               let result = h
                 .invoke_object_method_return_object(
-                  &clazz_method.clone(),
-                  &op_method_instance,
-                  invoke_method,
+                  &methods.clazz,
+                  &a,
+                  op_method,
                   current_thread,
-                  &[JDWPValue::Object(a), JDWPValue::Array(varargs)],
+                  &args,
                 )
                 .await?;
               stack.push(result);
-              print_done();
+              h.print_done();
             }
           }
         }
 
-        print_what_is_doing("Result obtained. call toString()");
-        let result_bigint = stack.pop().expect("Stack underflow");
+        h.print_what_is_doing("Result obtained. call toString()");
+        let mut result_number = stack.pop().expect("Stack underflow");
+        if let Some(strip_trailing_zeros) = &methods.strip_trailing_zeros {
+          result_number = h
+            .invoke_object_method_return_object(
+              &methods.clazz,
+              &result_number,
+              strip_trailing_zeros,
+              current_thread,
+              &[],
+            )
+            .await?;
+        }
+        let to_string_args = match methods.radix {
+          Some(radix) => vec![JDWPValue::Int(radix as i32)],
+          None => vec![],
+        };
         let result_string_obj = {
           h.invoke_object_method_return_object(
-            clazz_method,
-            to_string_method_instance,
-            invoke_method,
+            &methods.clazz,
+            &result_number,
+            &methods.to_string,
             current_thread,
-            &[
-              JDWPValue::Object(result_bigint),
-              JDWPValue::Array(
-                JDWPIDLengthEqObject::from_value(&vec![PrettyIOKind::Int(0)])
-                  .unwrap()
-                  .0,
-              ),
-            ],
+            &to_string_args,
           )
           .await?
         };
-        print_done();
+        h.print_done();
 
         // 文字列の内容を取得する
-        print_what_is_doing("Get string value");
+        h.print_what_is_doing("Get string value");
         let JDWPPacketDataFromDebuggee::StringReferenceValue(StringReferenceValueReceive {
           string_value,
         }) = h
@@ -1110,7 +1456,13 @@ impl SendHandler {
         else {
           panic!("Failed to get string value")
         };
-        print_done();
+        h.print_done();
+
+        h.print_info(&format!(
+          "Evaluated in {} ms with {} JDWP requests",
+          started.elapsed().as_millis(),
+          h.cmd_id - first_cmd_id
+        ));
 
         Ok(string_value.data)
       }
@@ -1118,3 +1470,38 @@ impl SendHandler {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_rebind_variable() {
+    let obj = |id| JDWPIDLengthEqObject { id };
+    let mut variables = HashMap::new();
+
+    assert!(rebind_variable(&mut variables, "a".to_string(), obj(1)).is_none());
+    // b = a
+    assert!(rebind_variable(&mut variables, "b".to_string(), obj(1)).is_none());
+    // a = 1 : b がまだ参照しているので回収させない
+    assert!(rebind_variable(&mut variables, "a".to_string(), obj(2)).is_none());
+    // x = x
+    assert!(rebind_variable(&mut variables, "a".to_string(), obj(2)).is_none());
+    // b を上書きすれば、どこからも参照されない
+    assert_eq!(
+      rebind_variable(&mut variables, "b".to_string(), obj(3)).map(|o| o.id),
+      Some(1)
+    );
+  }
+
+  #[test]
+  fn test_check_exponent() {
+    assert!(check_exponent(999_999_999, true).is_ok());
+    assert_eq!(
+      check_exponent(1_000_000_000, true),
+      Err("Exponent too large (max 999999999)".to_string())
+    );
+    assert!(check_exponent(1_000_000_000, false).is_ok());
+    assert!(check_exponent(i32::MAX, false).is_err());
+  }
+}
@@ -1,7 +1,21 @@
 #[derive(Debug, PartialEq, Eq)]
 pub enum Expression {
   Number(i64),
+  // 小数リテラル (--decimal 時のみ評価できる)
+  // 精度を落とさないよう、f64 を経由せずに文字列のまま BigDecimal に渡す
+  Decimal(String),
+  Variable(String),
+  Unary(UnaryOperator),
   Binary(Operator),
+  // スタックの先頭の値を累乗する (指数はパース時に決まる非負の int)
+  Power(i32),
+  // スタックの先頭の値を変数に束縛する (値はスタックに残す)
+  Assign(String),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum UnaryOperator {
+  Negate,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -10,18 +24,45 @@ pub enum Operator {
   Subtract,
   Multiply,
   Divide,
+  Modulo,
 }
 
 pub fn parse_input(input: &str) -> Result<Vec<Expression>, String> {
   // パース処理
   let mut exprs = Vec::new();
+  let (target, input) = parse_assignment_target(input);
   let remain = parse_expression(input, &mut exprs)?;
   if !remain.trim().is_empty() {
     return Err(format!("Unexpected input remaining: '{}'", remain));
   }
+  if let Some(name) = target {
+    exprs.push(Expression::Assign(name.to_string()));
+  }
   Ok(exprs)
 }
 
+// "識別子 =" の形の接頭辞があれば、識別子と残りの入力を返す
+fn parse_assignment_target(input: &str) -> (Option<&str>, &str) {
+  let s = input.trim_start();
+  if let Some((name, rest)) = split_identifier(s)
+    && let Some(rest) = rest.trim_start().strip_prefix('=')
+  {
+    return (Some(name), rest);
+  }
+  (None, input)
+}
+
+// 先頭の識別子 ([A-Za-z_][A-Za-z0-9_]*) を切り出す
+fn split_identifier(s: &str) -> Option<(&str, &str)> {
+  if !s.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+    return None;
+  }
+  let i = s
+    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+    .unwrap_or(s.len());
+  Some((&s[..i], &s[i..]))
+}
+
 pub fn parse_expression(input: &str, exprs: &mut Vec<Expression>) -> Result<String, String> {
   parse_add_sub(input, exprs)
 }
@@ -52,23 +93,28 @@ pub fn parse_add_sub(input: &str, exprs: &mut Vec<Expression>) -> Result<String,
   Ok(rest)
 }
 
-// * / のレベル
+// * / % のレベル
 pub fn parse_mul_div(input: &str, exprs: &mut Vec<Expression>) -> Result<String, String> {
   let mut rest;
 
-  // 最初の項（数字または括弧）をパース
-  rest = parse_primary(input, exprs)?;
+  // 最初の項（累乗の項）をパース
+  rest = parse_pow(input, exprs)?;
 
   loop {
     let rest_trimmed = rest.trim_start();
-    if rest_trimmed.starts_with('*') || rest_trimmed.starts_with('/') {
+    if rest_trimmed.starts_with('*')
+      || rest_trimmed.starts_with('/')
+      || rest_trimmed.starts_with('%')
+    {
       let op = if rest_trimmed.starts_with('*') {
         Operator::Multiply
-      } else {
+      } else if rest_trimmed.starts_with('/') {
         Operator::Divide
+      } else {
+        Operator::Modulo
       };
       let next_input = &rest_trimmed[1..];
-      rest = parse_primary(next_input, exprs)?;
+      rest = parse_pow(next_input, exprs)?;
       exprs.push(Expression::Binary(op));
     } else {
       break;
@@ -78,7 +124,70 @@ pub fn parse_mul_div(input: &str, exprs: &mut Vec<Expression>) -> Result<String,
   Ok(rest)
 }
 
-// 数字や括弧をパース
+// ^ (**) のレベル
+// BigInteger.pow は int しか取らないので、指数は非負の整数リテラルに限る
+// 右結合なので、2 ^ 3 ^ 2 は指数をパース時に 3 ^ 2 = 9 と畳み込む
+pub fn parse_pow(input: &str, exprs: &mut Vec<Expression>) -> Result<String, String> {
+  let mut rest = parse_unary(input, exprs)?;
+
+  let mut exponents = Vec::new();
+  loop {
+    let rest_trimmed = rest.trim_start();
+    let next_input = if let Some(next_input) = rest_trimmed.strip_prefix("**") {
+      next_input
+    } else if let Some(next_input) = rest_trimmed.strip_prefix('^') {
+      next_input
+    } else {
+      break;
+    };
+    let (exponent, remaining) = parse_exponent(next_input)?;
+    exponents.push(exponent);
+    rest = remaining.to_string();
+  }
+
+  if let Some((&last, init)) = exponents.split_last() {
+    let mut exponent = last;
+    for &base in init.iter().rev() {
+      exponent = base
+        .checked_pow(exponent as u32)
+        .ok_or(format!("Exponent too large (max {})", i32::MAX))?;
+    }
+    exprs.push(Expression::Power(exponent));
+  }
+
+  Ok(rest)
+}
+
+fn parse_exponent(input: &str) -> Result<(i32, &str), String> {
+  let s = input.trim_start();
+  let i = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+  if i == 0 || s[i..].starts_with('.') {
+    return Err(format!(
+      "Exponent must be a non-negative integer literal at '{}'",
+      s
+    ));
+  }
+  let exponent: i32 = s[..i]
+    .parse()
+    .map_err(|_| format!("Exponent too large (max {})", i32::MAX))?;
+  Ok((exponent, &s[i..]))
+}
+
+// 単項 + - のレベル
+pub fn parse_unary(input: &str, exprs: &mut Vec<Expression>) -> Result<String, String> {
+  let s = input.trim_start();
+  if let Some(operand) = s.strip_prefix('-') {
+    let rest = parse_unary(operand, exprs)?;
+    exprs.push(Expression::Unary(UnaryOperator::Negate));
+    Ok(rest)
+  } else if let Some(operand) = s.strip_prefix('+') {
+    parse_unary(operand, exprs)
+  } else {
+    parse_primary(s, exprs)
+  }
+}
+
+// 数字や変数、括弧をパース
 pub fn parse_primary(input: &str, exprs: &mut Vec<Expression>) -> Result<String, String> {
   let s = input.trim_start();
   if let Some(after_paren) = s.strip_prefix('(') {
@@ -89,25 +198,26 @@ pub fn parse_primary(input: &str, exprs: &mut Vec<Expression>) -> Result<String,
     } else {
       Err("Expected ')'".to_string())
     }
+  } else if let Some((name, rest)) = split_identifier(s) {
+    exprs.push(Expression::Variable(name.to_string()));
+    Ok(rest.to_string())
   } else {
     // 数字のパース
-    let chars = s.chars();
-    let mut i = 0;
-    for c in chars {
-      if c.is_ascii_digit() {
-        i += c.len_utf8();
-      } else {
-        break;
-      }
-    }
+    let digits_len = |s: &str| s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let mut i = digits_len(s);
     if i == 0 {
       return Err(format!("Expected number at '{}'", s));
     }
-    let num_str = &s[..i];
-    let rest = &s[i..];
-    let num: i64 = num_str.parse().map_err(|_| "Invalid number")?;
-    exprs.push(Expression::Number(num));
-    Ok(rest.to_string())
+    // 小数部があれば小数リテラル
+    let fraction_len = s[i..].strip_prefix('.').map(digits_len).unwrap_or(0);
+    if fraction_len > 0 {
+      i += 1 + fraction_len;
+      exprs.push(Expression::Decimal(s[..i].to_string()));
+    } else {
+      let num: i64 = s[..i].parse().map_err(|_| "Invalid number")?;
+      exprs.push(Expression::Number(num));
+    }
+    Ok(s[i..].to_string())
   }
 }
 
@@ -132,4 +242,133 @@ mod tests {
       ])
     );
   }
+
+  #[test]
+  fn test_parse_unary() {
+    assert_eq!(
+      parse_input("-5"),
+      Ok(vec![
+        Expression::Number(5),
+        Expression::Unary(UnaryOperator::Negate),
+      ])
+    );
+    assert_eq!(
+      parse_input("3*-2"),
+      Ok(vec![
+        Expression::Number(3),
+        Expression::Number(2),
+        Expression::Unary(UnaryOperator::Negate),
+        Expression::Binary(Operator::Multiply),
+      ])
+    );
+    assert_eq!(
+      parse_input("-(1+2)"),
+      Ok(vec![
+        Expression::Number(1),
+        Expression::Number(2),
+        Expression::Binary(Operator::Add),
+        Expression::Unary(UnaryOperator::Negate),
+      ])
+    );
+    assert_eq!(
+      parse_input("2 - -3"),
+      Ok(vec![
+        Expression::Number(2),
+        Expression::Number(3),
+        Expression::Unary(UnaryOperator::Negate),
+        Expression::Binary(Operator::Subtract),
+      ])
+    );
+    assert_eq!(parse_input("+7"), Ok(vec![Expression::Number(7)]));
+  }
+
+  #[test]
+  fn test_parse_modulo() {
+    assert_eq!(
+      parse_input("1 + 17 % 5 * 2"),
+      Ok(vec![
+        Expression::Number(1),
+        Expression::Number(17),
+        Expression::Number(5),
+        Expression::Binary(Operator::Modulo),
+        Expression::Number(2),
+        Expression::Binary(Operator::Multiply),
+        Expression::Binary(Operator::Add),
+      ])
+    );
+  }
+
+  #[test]
+  fn test_parse_variable() {
+    assert_eq!(
+      parse_input("x = 3 + 4"),
+      Ok(vec![
+        Expression::Number(3),
+        Expression::Number(4),
+        Expression::Binary(Operator::Add),
+        Expression::Assign("x".to_string()),
+      ])
+    );
+    assert_eq!(
+      parse_input("x_1 * 2"),
+      Ok(vec![
+        Expression::Variable("x_1".to_string()),
+        Expression::Number(2),
+        Expression::Binary(Operator::Multiply),
+      ])
+    );
+    assert!(parse_input("x = ").is_err());
+    assert!(parse_input("1 = 2").is_err());
+  }
+
+  #[test]
+  fn test_parse_decimal() {
+    assert_eq!(
+      parse_input("1.5 / 3"),
+      Ok(vec![
+        Expression::Decimal("1.5".to_string()),
+        Expression::Number(3),
+        Expression::Binary(Operator::Divide),
+      ])
+    );
+    assert_eq!(
+      parse_input("1.00000000000000000001"),
+      Ok(vec![Expression::Decimal(
+        "1.00000000000000000001".to_string()
+      )])
+    );
+    assert!(parse_input("1.").is_err());
+    assert!(parse_input("1.2.3").is_err());
+  }
+
+  #[test]
+  fn test_parse_pow() {
+    assert_eq!(
+      parse_input("3 * 2 ^ 10"),
+      Ok(vec![
+        Expression::Number(3),
+        Expression::Number(2),
+        Expression::Power(10),
+        Expression::Binary(Operator::Multiply),
+      ])
+    );
+    assert_eq!(
+      parse_input("2 ** 3 ^ 2"),
+      Ok(vec![Expression::Number(2), Expression::Power(9)])
+    );
+    assert_eq!(
+      parse_input("(1 + 1) ^ 3"),
+      Ok(vec![
+        Expression::Number(1),
+        Expression::Number(1),
+        Expression::Binary(Operator::Add),
+        Expression::Power(3),
+      ])
+    );
+    assert!(parse_input("2 ^ -1").is_err());
+    assert!(parse_input("2 ^ x").is_err());
+    assert!(parse_input("2 ^ 1.5").is_err());
+    assert!(parse_input("2 ^ 2147483648").is_err());
+    assert!(parse_input("2 ^ 2 ^ 31").is_err());
+  }
 }
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use ore_jdwp::packets::JDWPPacketDataFromDebugger;
+
+/// JDWP コマンド種別ごとの所要時間を集計する
+#[derive(Debug, Default)]
+pub struct Profiler {
+  entries: HashMap<String, ProfileEntry>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProfileEntry {
+  pub count: u32,
+  pub timed_out: u32,
+  pub total: Duration,
+}
+
+impl Profiler {
+  pub fn record(&mut self, payload: &JDWPPacketDataFromDebugger, elapsed: Duration) {
+    let entry = self.entries.entry(command_name(payload)).or_default();
+    entry.count += 1;
+    entry.total += elapsed;
+  }
+
+  /// タイムアウトしたリクエストも、待った時間ごと集計する
+  pub fn record_timeout(&mut self, payload: &JDWPPacketDataFromDebugger, elapsed: Duration) {
+    self.record(payload, elapsed);
+    if let Some(entry) = self.entries.get_mut(&command_name(payload)) {
+      entry.timed_out += 1;
+    }
+  }
+
+  /// 合計時間の降順に並べたエントリ
+  pub fn sorted_entries(&self) -> Vec<(&str, ProfileEntry)> {
+    let mut entries: Vec<_> = self
+      .entries
+      .iter()
+      .map(|(name, entry)| (name.as_str(), *entry))
+      .collect();
+    entries.sort_by(|a, b| b.1.total.cmp(&a.1.total).then(a.0.cmp(b.0)));
+    entries
+  }
+
+  pub fn report(&self) -> String {
+    let entries = self.sorted_entries();
+    let total: Duration = entries.iter().map(|(_, e)| e.total).sum();
+    let name_width = entries
+      .iter()
+      .map(|(name, _)| name.len())
+      .max()
+      .unwrap_or(0)
+      .max("Command".len());
+
+    let mut out = String::new();
+    out.push_str(&format!(
+      "{:<name_width$} {:>8} {:>9} {:>12} {:>10} {:>6}\n",
+      "Command", "Count", "Timeouts", "Total(ms)", "Avg(us)", "%"
+    ));
+    for (name, entry) in &entries {
+      let ratio = if total.is_zero() {
+        0.0
+      } else {
+        entry.total.as_secs_f64() / total.as_secs_f64() * 100.0
+      };
+      out.push_str(&format!(
+        "{:<name_width$} {:>8} {:>9} {:>12.3} {:>10.1} {:>6.1}\n",
+        name,
+        entry.count,
+        entry.timed_out,
+        entry.total.as_secs_f64() * 1000.0,
+        entry.total.as_secs_f64() * 1_000_000.0 / entry.count as f64,
+        ratio
+      ));
+    }
+    out.push_str(&format!(
+      "{:<name_width$} {:>8} {:>9} {:>12.3}\n",
+      "Total",
+      entries.iter().map(|(_, e)| e.count).sum::<u32>(),
+      entries.iter().map(|(_, e)| e.timed_out).sum::<u32>(),
+      total.as_secs_f64() * 1000.0
+    ));
+    out
+  }
+}
+
+/// ペイロードのバリアント名 (e.g. "VirtualMachineCreateString") を得る
+///
+/// derive された Debug の出力が `VariantName(..)` の形で始まることに依存している。
+/// ペイロード全体を整形するので、大きなパケットほどコストがかかる
+pub fn command_name(payload: &JDWPPacketDataFromDebugger) -> String {
+  format!("{:?}", payload)
+    .split(|c: char| !c.is_alphanumeric() && c != '_')
+    .next()
+    .unwrap_or_default()
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use ore_jdwp::defs::VirtualMachineCreateStringSend;
+
+  #[test]
+  fn test_profile_report() {
+    let mut profiler = Profiler::default();
+    profiler.record(
+      &JDWPPacketDataFromDebugger::VirtualMachineIDSizes(()),
+      Duration::from_millis(1),
+    );
+    for _ in 0..3 {
+      profiler.record(
+        &JDWPPacketDataFromDebugger::VirtualMachineCreateString(VirtualMachineCreateStringSend {
+          utf: "1".into(),
+        }),
+        Duration::from_millis(2),
+      );
+    }
+    profiler.record_timeout(
+      &JDWPPacketDataFromDebugger::VirtualMachineResume(()),
+      Duration::from_millis(100),
+    );
+
+    let entries = profiler.sorted_entries();
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[0].0, "VirtualMachineResume");
+    assert_eq!(entries[0].1.timed_out, 1);
+    assert_eq!(entries[1].0, "VirtualMachineCreateString");
+    assert_eq!(entries[1].1.count, 3);
+    assert_eq!(entries[1].1.timed_out, 0);
+    assert_eq!(entries[2].0, "VirtualMachineIDSizes");
+
+    let report = profiler.report();
+    assert!(report.contains("VirtualMachineCreateString"));
+    assert!(report.contains("VirtualMachineIDSizes"));
+  }
+}